@@ -18,7 +18,7 @@ use tracing::info;
 
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
-    pipeline::Pipeline,
+    pipeline::{format_prompt, Pipeline},
     prefix_cacher::PrefixCacheManager,
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
@@ -284,22 +284,8 @@ impl Engine {
             return;
         }
 
-        let mut force_tokens = None;
-        let formatted_prompt = match request.messages {
-            RequestMessage::Chat(messages) => {
-                let template = get_mut_arcmutex!(self.pipeline).apply_chat_template(messages, true);
-                handle_seq_error!(template, request.response)
-            }
-            RequestMessage::Completion { text, .. } => text,
-            RequestMessage::CompletionTokens(it) => {
-                let res = get_mut_arcmutex!(self.pipeline)
-                    .tokenizer()
-                    .decode(&it, false)
-                    .expect("cannot decode completion tokens");
-                force_tokens = Some(it);
-                res
-            }
-        };
+        let formatted = format_prompt(&*get_mut_arcmutex!(self.pipeline), &request.messages);
+        let (formatted_prompt, force_tokens) = handle_seq_error!(formatted, request.response);
         if formatted_prompt.is_empty() {
             request
                 .response
@@ -311,7 +297,7 @@ impl Engine {
             return;
         }
         let mut prompt = match force_tokens {
-            Some(tks) => tks,
+            Some(tks) => tks.to_vec(),
             None => {
                 let prompt = get_mut_arcmutex!(self.pipeline).tokenize_prompt(&formatted_prompt);
                 handle_seq_error!(prompt, request.response)
@@ -166,7 +166,7 @@ pub struct GenerationConfig {
 }
 
 pub fn apply_chat_template_to(
-    messages: Vec<IndexMap<String, String>>,
+    messages: &[IndexMap<String, String>],
     add_generation_prompt: bool,
     template: &str,
     bos_tok: Option<String>,
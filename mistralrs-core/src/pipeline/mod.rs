@@ -47,6 +47,7 @@ use candle_core::{DType, Device, Tensor};
 
 use crate::{
     models::Cache,
+    request::RequestMessage,
     sequence::Sequence,
     utils::tokens::get_token,
     xlora_models::{NonGranularState, XLoraConfig},
@@ -318,16 +319,26 @@ pub trait Pipeline: Send + Sync {
             .map_err(|e| anyhow::Error::msg(e.to_string()))?;
         Ok(encoding.get_ids().to_vec())
     }
+    /// Count the prompt tokens for `messages` without running the model. This formats the
+    /// prompt the same way the engine does before tokenizing it.
+    fn count_tokens(&self, messages: &RequestMessage) -> Result<usize> {
+        match format_prompt(self, messages)? {
+            (_, Some(toks)) => Ok(toks.len()),
+            (prompt, None) => Ok(self.tokenize_prompt(&prompt)?.len()),
+        }
+    }
     fn device(&self) -> Device;
     fn tokenizer(&self) -> Arc<Tokenizer>;
     fn name(&self) -> String;
     fn apply_chat_template(
         &self,
-        messages: Vec<IndexMap<String, String>>,
+        messages: &[IndexMap<String, String>],
         add_generation_prompt: bool,
     ) -> Result<String> {
         let chat_template = self.get_chat_template();
-        let template = chat_template.chat_template.as_ref().unwrap();
+        let Some(template) = chat_template.chat_template.as_ref() else {
+            anyhow::bail!("Received messages for a model which does not have a chat template.");
+        };
         let bos_tok = if let Some(ref bos) = self.get_chat_template().bos_token {
             match bos.0 {
                 Either::Left(ref lit) => Some(lit.to_string()),
@@ -374,6 +385,26 @@ pub trait Pipeline: Send + Sync {
     fn cache(&self) -> &Cache;
 }
 
+/// Format `messages` into the prompt text the model sees, applying the chat template to chat
+/// messages. For prompts given as tokens, the decoded text is returned along with the tokens,
+/// which should be used as-is rather than re-tokenizing the text.
+pub(crate) fn format_prompt<'a, P: Pipeline + ?Sized>(
+    pipeline: &P,
+    messages: &'a RequestMessage,
+) -> Result<(String, Option<&'a [u32]>)> {
+    match messages {
+        RequestMessage::Chat(messages) => Ok((pipeline.apply_chat_template(messages, true)?, None)),
+        RequestMessage::Completion { text, .. } => Ok((text.clone(), None)),
+        RequestMessage::CompletionTokens(toks) => {
+            let prompt = pipeline
+                .tokenizer()
+                .decode(toks, false)
+                .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+            Ok((prompt, Some(toks)))
+        }
+    }
+}
+
 pub trait CacheManager {
     fn clone_in_cache(&self, pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence]);
     fn clone_out_cache(&self, pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence]);
@@ -869,7 +900,127 @@ fn get_model_paths(
     }
 }
 
+#[cfg(test)]
 mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use candle_core::{quantized::GgmlDType, Device, Tensor};
+    use indexmap::IndexMap;
+    use rand_isaac::Isaac64Rng;
+    use tokenizers::Tokenizer;
+
+    use crate::{
+        aici::bintokens::build_tok_trie,
+        models::Cache,
+        pipeline::{ChatTemplate, GeneralMetadata, ModelInputs, Pipeline},
+        prefix_cacher::PrefixCacheManager,
+        request::RequestMessage,
+        response::{CompletionChoice, Response},
+        sampler::{Logprobs, Sampler, TopLogprob},
+        sequence::{Sequence, SequenceGroup, SequenceRecognizer},
+    };
+
+    /// A pipeline without a model, with a word level tokenizer and a simple chat template.
+    struct FakePipeline {
+        tokenizer: Arc<Tokenizer>,
+        chat_template: Arc<ChatTemplate>,
+        metadata: GeneralMetadata,
+    }
+
+    impl FakePipeline {
+        fn new() -> Self {
+            // Splits on whitespace and punctuation, with every word mapping to a single token.
+            let tokenizer = Tokenizer::from_str(
+                r#"{
+                    "version": "1.0",
+                    "truncation": null,
+                    "padding": null,
+                    "added_tokens": [],
+                    "normalizer": null,
+                    "pre_tokenizer": {"type": "Whitespace"},
+                    "post_processor": null,
+                    "decoder": {
+                        "type": "ByteLevel",
+                        "add_prefix_space": false,
+                        "trim_offsets": false,
+                        "use_regex": false
+                    },
+                    "model": {
+                        "type": "WordLevel",
+                        "vocab": {"<unk>": 0, "user": 1, "assistant": 2, ":": 3, "Hello": 4, "world": 5},
+                        "unk_token": "<unk>"
+                    }
+                }"#,
+            )
+            .unwrap();
+            let chat_template: ChatTemplate = serde_json::from_str(
+                r#"{
+                    "chat_template": "{% for message in messages %}{{ message['role'] + ': ' + message['content'] + '\n' }}{% endfor %}{% if add_generation_prompt %}{{ 'assistant:' }}{% endif %}",
+                    "eos_token": "</s>",
+                    "model_max_length": 1024,
+                    "tokenizer_class": "FakeTokenizer"
+                }"#,
+            )
+            .unwrap();
+            let metadata = GeneralMetadata {
+                max_seq_len: 1024,
+                repeat_last_n: 64,
+                tok_trie: Arc::new(build_tok_trie(tokenizer.clone())),
+                has_no_kv_cache: true,
+                is_xlora: false,
+                num_hidden_layers: 1,
+                eos_tok: vec![0],
+            };
+            Self {
+                tokenizer: Arc::new(tokenizer),
+                chat_template: Arc::new(chat_template),
+                metadata,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Pipeline for FakePipeline {
+        fn forward_inputs(&mut self, _: ModelInputs) -> Result<Tensor, candle_core::Error> {
+            unimplemented!()
+        }
+        async fn sample(
+            &self,
+            _: &mut [&mut Sequence],
+            _: Tensor,
+            _: &mut PrefixCacheManager,
+            _: bool,
+            _: Arc<std::sync::Mutex<Isaac64Rng>>,
+        ) -> Result<(), candle_core::Error> {
+            unimplemented!()
+        }
+        fn device(&self) -> Device {
+            Device::Cpu
+        }
+        fn tokenizer(&self) -> Arc<Tokenizer> {
+            self.tokenizer.clone()
+        }
+        fn name(&self) -> String {
+            "fake".to_string()
+        }
+        fn get_chat_template(&self) -> Arc<ChatTemplate> {
+            self.chat_template.clone()
+        }
+        fn reset_non_granular_state(&self) {}
+        fn get_metadata(&self) -> &GeneralMetadata {
+            &self.metadata
+        }
+        fn re_isq_model(&mut self, _: GgmlDType) -> anyhow::Result<()> {
+            unimplemented!()
+        }
+        fn clone_in_cache(&mut self, _: &mut [&mut Sequence]) {}
+        fn clone_out_cache(&mut self, _: &mut [&mut Sequence]) {}
+        fn set_none_cache(&mut self, _: bool) {}
+        fn cache(&self) -> &Cache {
+            unimplemented!()
+        }
+    }
+
     #[test]
     /// Generating these cases:
     /// ```py
@@ -880,8 +1031,6 @@ mod tests {
     /// >>> t.apply_chat_template([{"role":"system","content":"You are a helpful assistant"},{"role":"user","content":"Hello"},{"role":"assistant","content":"Hi there"},{"role":"user","content":"Who are you"},{"role":"assistant","content":"   I am an assistant   "},{"role":"user","content":"Another question"}], add_generation_prompt=True, tokenize=False)
     /// ```
     fn test_chat_templates() {
-        use indexmap::IndexMap;
-
         use crate::pipeline::apply_chat_template_to;
         let templates = [
             // ChatML: https://huggingface.co/teknium/OpenHermes-2.5-Mistral-7B
@@ -926,11 +1075,7 @@ mod tests {
             templates.into_iter().enumerate().zip(expected_outputs)
         {
             let output = apply_chat_template_to(
-                if !has_system {
-                    inputs[1..].to_vec()
-                } else {
-                    inputs.clone()
-                },
+                if !has_system { &inputs[1..] } else { &inputs },
                 true,
                 template,
                 Some(bos.to_string()),
@@ -941,4 +1086,103 @@ mod tests {
             assert_eq!(output, expected, "Template number {i}");
         }
     }
+
+    #[test]
+    fn test_count_tokens() {
+        let pipeline = FakePipeline::new();
+
+        let completion = RequestMessage::Completion {
+            text: "Hello world".to_string(),
+            echo_prompt: false,
+            best_of: 1,
+        };
+        assert_eq!(pipeline.count_tokens(&completion).unwrap(), 2);
+
+        // "user: Hello world\nassistant:"
+        let mut message = IndexMap::new();
+        message.insert("role".to_string(), "user".to_string());
+        message.insert("content".to_string(), "Hello world".to_string());
+        let chat = RequestMessage::Chat(vec![message]);
+        assert_eq!(pipeline.count_tokens(&chat).unwrap(), 6);
+
+        let tokens = RequestMessage::CompletionTokens(vec![4, 5, 4]);
+        assert_eq!(pipeline.count_tokens(&tokens).unwrap(), 3);
+    }
+
+    /// Run a completion sequence which finishes on its first sampled token through
+    /// `finish_and_add_tokens_to_seq!`, returning the choice it responds with.
+    fn finish_completion(return_logprobs: bool) -> CompletionChoice {
+        let pipeline = FakePipeline::new();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, 1,
+        )));
+        let sampler = Sampler::new(None, 1, pipeline.tokenizer(), None, None, None, -1, 1.0);
+        let mut seq = Sequence::new_waiting(
+            vec![4],
+            0,
+            0,
+            1,
+            tx,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            return_logprobs,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+        );
+        let mut prefix_cacher = PrefixCacheManager::new(Device::Cpu, 0, false, true);
+        let top_logprob = TopLogprob {
+            token: 5,
+            logprob: -0.5,
+            bytes: "world".to_string(),
+        };
+        let logprobs = Logprobs {
+            token: 5,
+            logprob: -0.5,
+            bytes: "world".to_string(),
+            top_logprobs: Some(vec![top_logprob]),
+        };
+        // Treat the sampled token as EOS so that the sequence finishes immediately.
+        let eos_tok = Some(&[5][..]);
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async {
+                let seq = &mut seq;
+                crate::finish_and_add_tokens_to_seq!(
+                    pipeline,
+                    prefix_cacher,
+                    seq,
+                    logprobs,
+                    eos_tok,
+                    false
+                );
+                Ok::<(), candle_core::Error>(())
+            })
+            .unwrap();
+
+        match rx.try_recv().unwrap() {
+            Response::CompletionDone(response) => response.choices[0].clone(),
+            _ => panic!("Expected a completion response."),
+        }
+    }
+
+    #[test]
+    fn test_completion_logprobs() {
+        let choice = finish_completion(true);
+        let content = choice.logprobs.unwrap().content.unwrap();
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].token, "world");
+        assert_eq!(content[0].logprob, -0.5);
+        assert_eq!(content[0].top_logprobs.len(), 1);
+
+        assert!(finish_completion(false).logprobs.is_none());
+    }
 }
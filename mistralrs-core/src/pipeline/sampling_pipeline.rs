@@ -14,7 +14,7 @@ macro_rules! finish_and_add_tokens_to_seq {
 
             if rate_limit_allowed {
                 if let Some(delta) =
-                    $crate::handle_seq_error_ok!($seq.get_delta(), $seq.responder())
+                    $crate::handle_seq_error_ok!($seq.get_delta(&is_done), $seq.responder())
                 {
                     $seq.add_streaming_chunk_choice_to_group($crate::ChunkChoice {
                         delta: $crate::Delta {
@@ -126,7 +126,7 @@ macro_rules! finish_and_add_tokens_to_seq {
                         finish_reason: reason.to_string(),
                         index: $seq.get_response_index(),
                         text,
-                        logprobs: None,
+                        logprobs: logprobs.map(|l| $crate::Logprobs { content: Some(l) }),
                     };
                     $seq.add_completion_choice_to_group(choice);
                 }
@@ -63,6 +63,53 @@ pub enum SequenceRecognizer {
     None,
 }
 
+/// Length of the longest suffix of `bytes` which is the start of (but not all of) a stop string.
+fn stop_string_prefix_len(bytes: &[u8], stop_strings: &[String]) -> usize {
+    stop_strings
+        .iter()
+        .map(|s| {
+            let s = s.as_bytes();
+            (1..s.len().min(bytes.len() + 1))
+                .rev()
+                .find(|n| bytes.ends_with(&s[..*n]))
+                .unwrap_or(0)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Decodes the part of `completion_bytes` after `stream_idx` that can be streamed now, returning
+/// it with the new stream index, or `None` if it has to be held back until more tokens arrive.
+fn next_delta(
+    completion_bytes: &[u8],
+    stream_idx: usize,
+    stop_strings: &[String],
+    is_done: &Option<StopReason>,
+) -> Option<(String, usize)> {
+    let end = match is_done {
+        // Bytes which may start a stop string are held back below, so the match never starts
+        // before `stream_idx`.
+        Some(StopReason::StopString {
+            completion_bytes_pos,
+            ..
+        }) => (*completion_bytes_pos).max(stream_idx),
+        Some(_) => completion_bytes.len(),
+        // A stop string may span several tokens, so hold back anything that could be its start.
+        None => {
+            completion_bytes.len()
+                - stop_string_prefix_len(&completion_bytes[stream_idx..], stop_strings)
+        }
+    };
+    let new_decoded = String::from_utf8_lossy(&completion_bytes[stream_idx..end]);
+    // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
+    if is_done.is_none()
+        && (new_decoded.ends_with('�') || (end == stream_idx && end < completion_bytes.len()))
+    {
+        return None;
+    }
+    Some((new_decoded.to_string(), end))
+}
+
 pub struct Sequence {
     // Metadata, const
     id: usize,
@@ -354,17 +401,24 @@ impl Sequence {
         &self.stop_strings
     }
 
-    /// Returns the delta between the last two decoded sequences
+    /// Returns the delta between the last two decoded sequences. Once the sequence is done
+    /// the remaining bytes are always flushed so the final chunk is never withheld, and a
+    /// matched stop string is cut off.
     pub fn get_delta(
         &mut self,
+        is_done: &Option<StopReason>,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
-        // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
-        if new_decoded.ends_with('�') {
-            return Ok(None);
-        }
-        self.stream_idx = self.completion_bytes.len();
+        let (new_decoded, end) = match next_delta(
+            &self.completion_bytes,
+            self.stream_idx,
+            &self.stop_strings,
+            is_done,
+        ) {
+            Some(delta) => delta,
+            None => return Ok(None),
+        };
+        self.stream_idx = end;
 
         // The first token usually starts with a space. We don't want to add that to the delta.
         // Since we're using the completion_bytes, we need to take care of that ourselves.
@@ -546,3 +600,57 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{next_delta, StopReason};
+
+    #[test]
+    fn test_delta_holds_back_partial_utf8() {
+        // "hi " followed by the first two bytes of the 4 byte "🦀".
+        let bytes = [b"hi ".as_slice(), &"🦀".as_bytes()[..2]].concat();
+        assert_eq!(next_delta(&bytes, 0, &[], &None), None);
+
+        let bytes = [b"hi ".as_slice(), "🦀".as_bytes()].concat();
+        assert_eq!(
+            next_delta(&bytes, 0, &[], &None),
+            Some(("hi 🦀".to_string(), bytes.len()))
+        );
+    }
+
+    #[test]
+    fn test_delta_flushes_partial_utf8_when_done() {
+        let bytes = [b"hi ".as_slice(), &"🦀".as_bytes()[..2]].concat();
+        let (delta, end) = next_delta(&bytes, 0, &[], &Some(StopReason::Eos)).unwrap();
+        assert!(delta.starts_with("hi "));
+        assert_eq!(end, bytes.len());
+    }
+
+    #[test]
+    fn test_delta_stops_at_stop_string_spanning_tokens() {
+        let stop_strings = vec!["###".to_string()];
+
+        // First token `#` could start the stop string, so it is held back.
+        let (delta, stream_idx) = next_delta(b"ab#", 0, &stop_strings, &None).unwrap();
+        assert_eq!((delta.as_str(), stream_idx), ("ab", 2));
+        assert_eq!(next_delta(b"ab#", stream_idx, &stop_strings, &None), None);
+
+        // Second token `##` completes it, so the final delta is empty.
+        let is_done = Some(StopReason::StopString {
+            stop_string_idx: 0,
+            completion_bytes_pos: 2,
+        });
+        let (delta, stream_idx) =
+            next_delta(b"ab###", stream_idx, &stop_strings, &is_done).unwrap();
+        assert_eq!((delta.as_str(), stream_idx), ("", 2));
+    }
+
+    #[test]
+    fn test_delta_releases_held_back_non_stop_string() {
+        let stop_strings = vec!["###".to_string()];
+
+        let (_, stream_idx) = next_delta(b"ab#", 0, &stop_strings, &None).unwrap();
+        let (delta, stream_idx) = next_delta(b"ab#c", stream_idx, &stop_strings, &None).unwrap();
+        assert_eq!((delta.as_str(), stream_idx), ("#c", 4));
+    }
+}
@@ -49,6 +49,8 @@ class CompletionRequest:
     suffix: str | None = None
     grammar: str | None = None
     grammar_type: str | None = None
+    logprobs: bool = False
+    top_logprobs: int | None = None
 
 @dataclass
 class Architecture(Enum):
@@ -269,7 +271,7 @@ class CompletionChoice:
     finish_reason: str
     index: int
     text: str
-    # NOTE(EricLBuehler): `logprobs` in undocumented
+    logprobs: Logprobs | None
 
 @dataclass
 class CompletionResponse:
@@ -83,6 +83,9 @@ struct Runner {
 
 static NEXT_REQUEST_ID: Mutex<RefCell<usize>> = Mutex::new(RefCell::new(0));
 
+/// OpenAI allows at most this many log probabilities per token for completions.
+const MAX_COMPLETION_LOGPROBS: usize = 5;
+
 #[pymethods]
 impl Runner {
     #[new]
@@ -527,6 +530,14 @@ impl Runner {
         let (tx, mut rx) = channel(10_000);
         Python::with_gil(|py| {
             let request = request.bind(py).borrow();
+            if let Some(n) = request
+                .top_logprobs
+                .filter(|n| *n > MAX_COMPLETION_LOGPROBS)
+            {
+                return Err(PyValueError::new_err(format!(
+                    "Completion requests support at most {MAX_COMPLETION_LOGPROBS} logprobs, got {n}."
+                )));
+            }
             let stop_toks = request
                 .stop_seqs
                 .as_ref()
@@ -569,7 +580,7 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
-                    top_n_logprobs: 1,
+                    top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
                     max_len: request.max_tokens,
@@ -578,7 +589,7 @@ impl Runner {
                     n_choices: request.n_choices,
                 },
                 response: tx,
-                return_logprobs: false,
+                return_logprobs: request.logprobs,
                 is_streaming: false,
                 constraint,
                 suffix: request.suffix.clone(),
@@ -633,6 +644,8 @@ struct CompletionRequest {
     top_k: Option<usize>,
     grammar: Option<String>,
     grammar_type: Option<String>,
+    logprobs: bool,
+    top_logprobs: Option<usize>,
 }
 
 #[pymethods]
@@ -654,7 +667,9 @@ impl CompletionRequest {
         suffix=None,
         top_k=None,
         grammar = None,
-        grammar_type = None
+        grammar_type = None,
+        logprobs = false,
+        top_logprobs = None
     ))]
     fn new(
         prompt: String,
@@ -673,6 +688,8 @@ impl CompletionRequest {
         top_k: Option<usize>,
         grammar: Option<String>,
         grammar_type: Option<String>,
+        logprobs: bool,
+        top_logprobs: Option<usize>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -691,6 +708,8 @@ impl CompletionRequest {
             top_k,
             grammar,
             grammar_type,
+            logprobs,
+            top_logprobs,
         })
     }
 }
@@ -1,3 +1,4 @@
+use anyhow::Result;
 use std::{error::Error, sync::Arc};
 use tokio::sync::mpsc::{channel, Sender};
 
@@ -82,11 +83,25 @@ impl IntoResponse for CompletionResponder {
     }
 }
 
+/// OpenAI allows at most this many log probabilities per token for completions.
+const MAX_LOGPROBS: usize = 5;
+
+/// Maps the OpenAI `logprobs` parameter to `(return_logprobs, top_n_logprobs)`.
+fn parse_logprobs(logprobs: Option<usize>) -> Result<(bool, usize)> {
+    match logprobs {
+        Some(n) if n > MAX_LOGPROBS => {
+            anyhow::bail!("Completion requests support at most {MAX_LOGPROBS} logprobs, got {n}.")
+        }
+        Some(n) => Ok((true, n)),
+        None => Ok((false, 1)),
+    }
+}
+
 fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
-) -> Request {
+) -> Result<Request> {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
 
@@ -96,15 +111,13 @@ fn parse_request(
         None => None,
     };
 
-    if oairequest.logprobs.is_some() {
-        info!("⚠️ WARNING: Completion requests do not support logprobs.");
-    }
-
     if oairequest._stream.is_some_and(|x| x) {
         info!("⚠️ WARNING: Completion requests do not support streaming.");
     }
 
-    Request {
+    let (return_logprobs, top_n_logprobs) = parse_logprobs(oairequest.logprobs)?;
+
+    Ok(Request {
         id: state.next_request_id(),
         messages: RequestMessage::Completion {
             text: oairequest.prompt,
@@ -115,7 +128,7 @@ fn parse_request(
             temperature: oairequest.temperature,
             top_k: oairequest.top_k,
             top_p: oairequest.top_p,
-            top_n_logprobs: 1,
+            top_n_logprobs,
             frequency_penalty: oairequest.frequency_penalty,
             presence_penalty: oairequest.presence_penalty,
             max_len: oairequest.max_tokens,
@@ -124,7 +137,7 @@ fn parse_request(
             n_choices: oairequest.n_choices,
         },
         response: tx,
-        return_logprobs: false,
+        return_logprobs,
         is_streaming: false,
         suffix: oairequest.suffix,
         constraint: match oairequest.grammar {
@@ -132,7 +145,7 @@ fn parse_request(
             Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
             None => Constraint::None,
         },
-    }
+    })
 }
 
 #[utoipa::path(
@@ -147,16 +160,16 @@ pub async fn completions(
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
     let (tx, mut rx) = channel(10_000);
-    let request = parse_request(oairequest, state.clone(), tx);
+    let request = match parse_request(oairequest, state.clone(), tx) {
+        Ok(request) => request,
+        Err(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            return CompletionResponder::ValidationError(e.into());
+        }
+    };
     let is_streaming = request.is_streaming;
     let sender = state.get_sender();
 
-    if request.return_logprobs {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support logprobs.".into(),
-        );
-    }
-
     if is_streaming {
         return CompletionResponder::ValidationError(
             "Completion requests do not support streaming.".into(),
@@ -198,3 +211,18 @@ pub async fn completions(
         Response::ModelError(_, _) => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_logprobs;
+
+    #[test]
+    fn test_parse_logprobs() {
+        assert_eq!(parse_logprobs(None).unwrap(), (false, 1));
+        assert_eq!(parse_logprobs(Some(0)).unwrap(), (true, 0));
+        assert_eq!(parse_logprobs(Some(3)).unwrap(), (true, 3));
+        assert_eq!(parse_logprobs(Some(5)).unwrap(), (true, 5));
+        assert!(parse_logprobs(Some(6)).is_err());
+        assert!(parse_logprobs(Some(usize::MAX)).is_err());
+    }
+}